@@ -1,87 +1,159 @@
-use aes_gcm::{AeadInPlace, Aes128Gcm, Key, Nonce};
+use aes_gcm::{AeadInPlace, Aes128Gcm, Aes256Gcm, Key, Nonce};
 use aes_gcm::aead::NewAead;
 use anyhow::{anyhow, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
 use base64;
-use generic_array::typenum::{U12, U16};
+use chacha20poly1305::ChaCha20Poly1305;
+use generic_array::typenum::{U12, U16, U32};
+use hkdf::Hkdf;
 use rand::RngCore;
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{fs, thread};
-use std::thread::JoinHandle;
+use std::collections::{HashMap, HashSet};
 use std::fs::{File, OpenOptions};
 use std::io::prelude::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
 use uuid::Uuid;
 
+const KDF_M_COST: u32 = 19456;
+const KDF_T_COST: u32 = 2;
+const KDF_P_COST: u32 = 1;
+const MASTER_KEY_LEN: usize = 32;
+
+type MasterKey = [u8; MASTER_KEY_LEN];
+
+// Number of worker threads to run a chunk pool with: the caller's explicit
+// override, or the machine's available parallelism as a fallback.
+fn worker_count(num_workers: Option<usize>) -> usize {
+    num_workers.unwrap_or_else(|| {
+        thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+    })
+}
+
 pub fn encrypt_file(
     path_in: &Path,
     path_out: &Path,
-    chunk_len: usize,
+    avg_size: usize,
+    min_size: Option<usize>,
+    max_size: Option<usize>,
+    passphrase: &str,
+    encryption_type: EncryptionType,
+    num_workers: Option<usize>,
 ) -> Result<Metadata> {
-    check_chunk_len(chunk_len)?;
-    let mut file = File::open(path_in)?;
-    let mut file_len = 0;
-    let mut chunks = Vec::new();
-    let mut threads = Vec::new();
+    let cdc = CdcParams::new(avg_size, min_size, max_size)?;
+    let kdf = KdfParams::generate(passphrase)?;
+    let master_key = kdf.derive_master_key(passphrase)?;
+    // Chunk lengths depend on every byte up to each cut point, and the loop
+    // below uses the total chunk count to tell which chunk is final (the only
+    // one that is padded and carries the position-binding AAD), so a full
+    // scan happens before the first chunk is encrypted. Scan with a window
+    // bounded by `max_size` rather than buffering the whole file, so RSS
+    // stays flat regardless of file size; the chunk bytes themselves are
+    // then re-read one at a time from the same file in the loop below.
+    let chunk_lens = {
+        let mut file = File::open(path_in)?;
+        cdc.scan(&mut file)?
+    };
+    let file_len: usize = chunk_lens.iter().sum();
+    let total_chunks = chunk_lens.len() as u64;
     fs::create_dir_all(path_out)?;
-    loop {
-        let mut buffer = Vec::with_capacity(chunk_len);
-        let num_bytes =
-            (&mut file)
-                .take(chunk_len as u64)
-                .read_to_end(&mut buffer)?;
-        if num_bytes == 0 {
-            break;
-        }
-        for _ in num_bytes..chunk_len {
-            buffer.push(0);
-        }
-        file_len += num_bytes;
-        let (chunk, path) = {
-            let chunk = Chunk::random();
-            let path = path_out.join(chunk.id_string());
-            let clone = chunk.clone();
-            chunks.push(chunk);
-            (clone, path)
-        };
-        let mut file =
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(path)?;
-        let thread: JoinHandle<Result<()>> =
-            thread::spawn(move || {
-                chunk.encrypt(&mut buffer);
-                file.write_all(buffer.as_slice())?;
-                Ok(())
-            });
-        threads.push(thread);
+    let num_workers = worker_count(num_workers);
+    let (job_tx, job_rx) = mpsc::sync_channel::<EncryptJob>(num_workers * 2);
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    let mut workers = Vec::new();
+    for _ in 0..num_workers {
+        let job_rx = Arc::clone(&job_rx);
+        workers.push(thread::spawn(move || -> Result<()> {
+            loop {
+                let job = job_rx.lock().unwrap().recv();
+                let EncryptJob { chunk, mut buffer, aad, path } = match job {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                chunk.encrypt(&mut buffer, &aad);
+                let mut out_file =
+                    OpenOptions::new()
+                        .create(true)
+                        .write(true)
+                        .open(path)?;
+                out_file.write_all(buffer.as_slice())?;
+            }
+            Ok(())
+        }));
     }
-    for thread in threads {
-        thread.join().unwrap()?;
+    let mut chunk_records = Vec::new();
+    let mut file = File::open(path_in)?;
+    let mut written_ids = HashSet::new();
+    for (index, len) in chunk_lens.into_iter().enumerate() {
+        let is_final = index as u64 + 1 == total_chunks;
+        let mut buffer = vec![0u8; len];
+        file.read_exact(&mut buffer)?;
+        if is_final {
+            pkcs7_pad(&mut buffer);
+        }
+        // Non-final chunks are addressed by the hash of their own plaintext,
+        // so identical content anywhere in the file re-derives the same
+        // id/key/nonce and this loop only writes its ciphertext once; the
+        // position-binding AAD (see `chunk_aad`) is dropped for these chunks
+        // since sharing one ciphertext across multiple positions would
+        // otherwise fail GCM re-authentication at every position but one.
+        // This dedups only within a single run: each run derives a fresh
+        // `master_key` from a fresh salt, so the same content re-encrypts to
+        // different ciphertext on the next run.
+        let chunk =
+            if is_final {
+                Chunk::random(&master_key, encryption_type)
+            } else {
+                Chunk::content_addressed(&master_key, encryption_type, &buffer)
+            };
+        chunk_records.push(chunk.record());
+        let aad =
+            if is_final {
+                chunk_aad(index as u64, total_chunks, file_len as u64, true)
+            } else {
+                Vec::new()
+            };
+        let path = path_out.join(chunk.id_string());
+        if !is_final && !written_ids.insert(chunk.id_string()) {
+            continue;
+        }
+        job_tx.send(EncryptJob { chunk, buffer, aad, path }).unwrap();
     }
-    Ok(Metadata { file_len, chunk_len, chunks })
+    drop(job_tx);
+    for worker in workers {
+        worker.join().unwrap()?;
+    }
+    Ok(Metadata { file_len, cdc: Some(cdc), chunk_records, kdf, encryption_type })
+}
+
+struct EncryptJob {
+    chunk: Chunk,
+    buffer: Vec<u8>,
+    aad: Vec<u8>,
+    path: PathBuf,
 }
 
 pub fn encrypt_file_unchunked(
     path_in: &Path,
     path_out: &Path,
+    passphrase: &str,
+    encryption_type: EncryptionType,
 ) -> Result<Metadata> {
+    let kdf = KdfParams::generate(passphrase)?;
+    let master_key = kdf.derive_master_key(passphrase)?;
     let mut file_in = File::open(path_in)?;
     let mut buffer = Vec::new();
     let file_len = (&mut file_in).read_to_end(&mut buffer)?;
-    let chunk = Chunk::random();
+    let chunk = Chunk::random(&master_key, encryption_type);
     fs::create_dir_all(path_out)?;
     let path = path_out.join(chunk.id_string());
-    let num_padding_bytes =
-        if file_len % 16 == 0 { 0 }
-        else { 16 - file_len % 16 };
-    for _ in 0..num_padding_bytes {
-        buffer.push(0);
-    }
-    let chunk_len = buffer.len() + 16;
-    buffer.reserve(chunk_len - buffer.len());
-    chunk.encrypt(&mut buffer);
+    let aad = chunk_aad(0, 1, file_len as u64, true);
+    pkcs7_pad(&mut buffer);
+    chunk.encrypt(&mut buffer, &aad);
     let mut file_out =
         OpenOptions::new()
             .create(true)
@@ -90,8 +162,10 @@ pub fn encrypt_file_unchunked(
     file_out.write_all(buffer.as_slice())?;
     Ok(Metadata {
         file_len,
-        chunk_len,
-        chunks: vec![chunk],
+        cdc: None,
+        chunk_records: vec![chunk.record()],
+        kdf,
+        encryption_type,
     })
 }
 
@@ -99,150 +173,640 @@ pub fn decrypt_file(
     path_in: &Path,
     path_out: &Path,
     metadata: &Metadata,
+    passphrase: &str,
+    num_workers: Option<usize>,
 ) -> Result<()> {
-    let Metadata { file_len, chunk_len, chunks } = metadata;
-    check_chunk_len(*chunk_len)?;
-    check_num_chunks(*file_len, *chunk_len, chunks.len())?;
+    let Metadata { file_len, chunk_records, kdf, encryption_type, .. } = metadata;
+    let master_key = kdf.verify(passphrase)?;
+    let total_chunks = chunk_records.len() as u64;
     let mut file =
         OpenOptions::new()
             .create(true)
             .write(true)
             .open(path_out)?;
-    let mut threads = Vec::new();
-    for chunk in chunks {
-        let chunk = chunk.clone();
-        let path = path_in.join(chunk.id_string());
-        let mut buffer = Vec::with_capacity(*chunk_len);
-        let thread: JoinHandle<Result<Vec<u8>>> = 
-            thread::spawn(move || {
-                let mut file = File::open(path)?;
-                file.read_to_end(&mut buffer)?;
-                chunk.decrypt(&mut buffer);
-                Ok(buffer)
+    let num_workers = worker_count(num_workers);
+    let (job_tx, job_rx) = mpsc::sync_channel::<DecryptJob<'_>>(num_workers * 2);
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    let (result_tx, result_rx) = mpsc::sync_channel::<Result<DecryptResult>>(num_workers * 2);
+    thread::scope(|scope| -> Result<()> {
+        for _ in 0..num_workers {
+            let job_rx = Arc::clone(&job_rx);
+            let result_tx = result_tx.clone();
+            scope.spawn(move || {
+                loop {
+                    let job = job_rx.lock().unwrap().recv();
+                    let job: DecryptJob = match job {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    let result = (|| -> Result<DecryptResult> {
+                        let chunk = Chunk::from_record(job.record, &master_key, *encryption_type)?;
+                        let mut buffer = Vec::new();
+                        File::open(&job.path)?.read_to_end(&mut buffer)?;
+                        chunk.decrypt(&mut buffer, &job.aad)?;
+                        if job.is_final {
+                            pkcs7_unpad(&mut buffer)?;
+                        }
+                        Ok(DecryptResult { index: job.index, data: buffer })
+                    })();
+                    if result_tx.send(result).is_err() {
+                        break;
+                    }
+                }
             });
-        threads.push(thread);
-    }
-    let last_len = {
-        let remainder = *file_len % *chunk_len;
-        if remainder == 0 { *chunk_len }
-        else { remainder }
-    };
-    let mut i = 0;
-    let last_idx = threads.len() - 1;
-    for thread in threads {
-        let data = thread.join().unwrap()?;
-        let slice =
-            if i < last_idx { &data[..] }
-            else { &data[..last_len] };
-        file.write_all(slice)?;
-        i += 1;
-    }
-    Ok(())
+        }
+        drop(result_tx);
+        // Dispatch on its own thread so the main thread can start draining
+        // `result_rx` right away instead of after every job is sent: with
+        // both channels bounded at `2 * num_workers`, sending every job before
+        // reading any result deadlocks as soon as there are more than roughly
+        // `5 * num_workers` chunks (two full channels plus one result held by
+        // each worker), since workers then block writing a full result
+        // channel while this thread blocks writing a full job channel.
+        scope.spawn(move || {
+            for (index, record) in chunk_records.iter().enumerate() {
+                let is_final = index as u64 + 1 == total_chunks;
+                let aad =
+                    if is_final {
+                        chunk_aad(index as u64, total_chunks, *file_len as u64, true)
+                    } else {
+                        Vec::new()
+                    };
+                let path = path_in.join(format_chunk_id(&record.id));
+                let job = DecryptJob { index: index as u64, record, path, aad, is_final };
+                if job_tx.send(job).is_err() {
+                    break;
+                }
+            }
+        });
+        // Workers may finish out of order; buffer completed chunks here until the
+        // next one needed to write the output file in order becomes available,
+        // which keeps memory bounded to roughly `num_workers` chunks in flight.
+        let mut pending = HashMap::new();
+        let mut next_index = 0u64;
+        let mut written = 0;
+        for result in result_rx {
+            let DecryptResult { index, data } = result?;
+            pending.insert(index, data);
+            while let Some(data) = pending.remove(&next_index) {
+                written += data.len();
+                file.write_all(&data)?;
+                next_index += 1;
+            }
+        }
+        if next_index != total_chunks {
+            return Err(anyhow!("expected {} chunks, found {}", total_chunks, next_index));
+        }
+        if written != *file_len {
+            return Err(anyhow!("expected {} decrypted bytes, found {}", file_len, written));
+        }
+        Ok(())
+    })
 }
 
-fn check_chunk_len(chunk_len: usize) -> Result<()> {
-    if chunk_len % 16 == 0 { Ok(()) }
-    else { Err(anyhow!("chunk_len must be a multiple of 16")) }
+struct DecryptJob<'a> {
+    index: u64,
+    record: &'a ChunkRecord,
+    path: PathBuf,
+    aad: Vec<u8>,
+    is_final: bool,
 }
 
-fn check_num_chunks(
-    file_len: usize,
-    chunk_len: usize,
-    num_chunks: usize,
-) -> Result<()> {
-    let expected = (file_len + chunk_len - 16) / (chunk_len - 16);
-    if num_chunks != expected {
-        Err(anyhow!("expected {} chunks, found {}", expected, num_chunks))
-    }
-    else { Ok(())}
+struct DecryptResult {
+    index: u64,
+    data: Vec<u8>,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct Metadata {
     file_len: usize,
-    chunk_len: usize,
-    chunks: Vec<Chunk>,
+    cdc: Option<CdcParams>,
+    chunk_records: Vec<ChunkRecord>,
+    kdf: KdfParams,
+    encryption_type: EncryptionType,
 }
 
-#[derive(Clone, Deserialize, Serialize)]
-#[serde(into = "ChunkIntermediate", try_from = "ChunkIntermediate")]
-pub struct Chunk {
+// A chunk's id and the nonce it was encrypted with (random for a chunk made
+// by `Chunk::random`, HKDF-derived for `Chunk::content_addressed`). The nonce no
+// longer derives from the key (the two must be independent for AES-GCM), so
+// it has to travel alongside the id instead.
+#[derive(Deserialize, Serialize)]
+struct ChunkRecord {
     id: Uuid,
-    key: Key<U16>,
+    nonce: String,
 }
 
-impl Chunk {
+impl std::fmt::Display for Metadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let json = serde_json::to_string_pretty(self).map_err(|_| std::fmt::Error)?;
+        write!(f, "{}", json)
+    }
+}
 
-    pub fn random() -> Self {
-        let random = || {
-            let mut bytes = [0u8; 16];
-            OsRng.fill_bytes(&mut bytes);
-            bytes
-        };
-        let id = {
-            let bytes = random();
-            Uuid::from_bytes(&bytes).unwrap()
+impl std::str::FromStr for Metadata {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(serde_json::from_str(s)?)
+    }
+}
+
+// Content-defined chunking via a Gear-hash rolling fingerprint (FastCDC),
+// with normalized chunking so chunk sizes cluster around `avg_size` instead
+// of following the geometric distribution a single mask would produce.
+// Cutting on content rather than a fixed offset means an insertion near the
+// start of the file only reshuffles the chunk(s) it touches, instead of
+// reshuffling every chunk after it the way fixed-size chunking would.
+//
+// Note on dedup: non-final chunks are addressed by the hash of their own
+// plaintext (see `Chunk::content_addressed`), so repeated content within one
+// file is written to disk only once. That exempts those chunks from the
+// position-binding AAD (see `chunk_aad`) that chunk0-3 added, since sharing
+// one ciphertext across multiple positions would fail GCM re-authentication
+// at every position but one — only the final chunk keeps position-binding.
+// This is a within-run dedup only: each run derives a fresh master key from
+// a fresh salt, so re-encrypting a changed file on a later run does not
+// reuse ciphertext from the earlier one.
+#[derive(Clone, Deserialize, Serialize)]
+struct CdcParams {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+}
+
+impl CdcParams {
+
+    // `min_size`/`max_size` default to `avg_size / 4` and `avg_size * 4` when
+    // not given explicitly, which is the same ratio FastCDC's reference
+    // implementation uses.
+    fn new(avg_size: usize, min_size: Option<usize>, max_size: Option<usize>) -> Result<Self> {
+        if avg_size == 0 {
+            return Err(anyhow!("avg_size must not be zero"));
+        }
+        let min_size = min_size.unwrap_or(avg_size / 4);
+        let max_size = max_size.unwrap_or(avg_size * 4);
+        if min_size > avg_size {
+            return Err(anyhow!("min_size must not exceed avg_size"));
+        }
+        if max_size < avg_size {
+            return Err(anyhow!("max_size must not be less than avg_size"));
+        }
+        Ok(CdcParams { min_size, avg_size, max_size })
+    }
+
+    // Number of low fingerprint bits that must be zero for a cut to occur.
+    // A larger bit count is harder to satisfy (fewer, later cuts); we test a
+    // stricter mask while below `avg_size` and a looser one once past it, so
+    // chunk boundaries converge toward the average instead of spreading out.
+    fn mask(&self, bits: u32) -> u64 {
+        if bits >= 64 { u64::MAX } else { (1u64 << bits) - 1 }
+    }
+
+    fn avg_bits(&self) -> u32 {
+        (self.avg_size as f64).log2().round() as u32
+    }
+
+    // Scans `reader` end-to-end and returns the length of every chunk it
+    // would be cut into, without holding more than `max_size` bytes (plus one
+    // read's worth of slack) in memory at a time regardless of the total
+    // input size.
+    fn scan<R: Read>(&self, reader: &mut R) -> Result<Vec<usize>> {
+        let mut lens = Vec::new();
+        let mut window = Vec::new();
+        let mut read_buf = vec![0u8; 64 * 1024];
+        loop {
+            while window.len() < self.max_size {
+                let n = reader.read(&mut read_buf)?;
+                if n == 0 {
+                    break;
+                }
+                window.extend_from_slice(&read_buf[..n]);
+            }
+            if window.is_empty() {
+                return Ok(lens);
+            }
+            let len = self.next_cut(&window);
+            lens.push(len);
+            window.drain(..len);
+        }
+    }
+
+    // Returns the length of the next chunk to cut from the front of `data`.
+    fn next_cut(&self, data: &[u8]) -> usize {
+        let limit = data.len().min(self.max_size);
+        if limit <= self.min_size {
+            return limit;
+        }
+        let avg_bits = self.avg_bits();
+        let mask_s = self.mask(avg_bits + 1);
+        let mask_l = self.mask(avg_bits.saturating_sub(1));
+        let mut fp: u64 = 0;
+        for i in self.min_size..limit {
+            fp = (fp << 1).wrapping_add(GEAR[data[i] as usize]);
+            let mask = if i < self.avg_size { mask_s } else { mask_l };
+            if fp & mask == 0 {
+                return i + 1;
+            }
+        }
+        limit
+    }
+
+}
+
+// A fixed table of 256 pseudorandom 64-bit values used to roll the Gear-hash
+// fingerprint in `CdcParams::next_cut`. Any fixed table works as long as it
+// is reused consistently across runs; it carries no secret material.
+const GEAR: [u64; 256] = [
+    0x9910c5ceb331c003, 0x2cc1cc4ef4575d35, 0x88a6eac741153ce0, 0x08aba04f2b30b639,
+    0x9003f127bbd4651b, 0x8ff49c33662f51f9, 0x0f32269597bff0ea, 0x8a108b102c197f44,
+    0x91eebf76547f1261, 0x803525bc9799ffef, 0xd10f50ed77ccd703, 0x12345d855b1aac2e,
+    0x84a7cb89c400949b, 0xc1b461cc3f4be13c, 0xf2c736314b83c243, 0x3258607b5c0f9f1b,
+    0x6741d90639f5c03c, 0x4784ef8fb4ddfcde, 0x11a40a013e995faf, 0x10b92e62b1c4cb9e,
+    0x61b9440a7a208f04, 0x3aeaebb2649d3d05, 0xf8104e736ae35e1f, 0xcfe925b8d408ef0b,
+    0xad0080996523a9a3, 0xb5ea6caddf723e57, 0x89333b427c7d6e61, 0x71f5005ba8fb0051,
+    0xc29869c00b493b0b, 0x192b4fed58e52883, 0x1fb28e04b3257e21, 0x206d81f0f1e49527,
+    0x3e867388450d6a7d, 0x5a5336b842121ac3, 0x7d6294d0958af8e7, 0x2f56de5a1f58d912,
+    0x008cfc0d45b9a836, 0x99d2ac815abd8f09, 0xb8e68784a27a90e5, 0xa1abbeb2ac5c5e34,
+    0x7c342e211278037f, 0xcac30f915e8f6acf, 0xa9f95bddd2ffae74, 0x6eedd485b3d03fcd,
+    0x5abf3806156c5a8b, 0xba5cd7f75045b9ae, 0x62e6faf975aa0730, 0xf50e97b2d3cff5cf,
+    0x397501e3554df5cc, 0x9421dbead190af40, 0x8770b6339effb99a, 0x66471c33e96e7a28,
+    0xc22977fc0b66f8e9, 0xc88cdf359a5303fb, 0xcd26f6179bfac41b, 0x44463d4cb0d07b92,
+    0x64a7cae74783d7b4, 0xfe5d26ddc514512c, 0xd6bf42ef33a87942, 0x802445bd65e5392e,
+    0x39ed8d1b6663410a, 0xf5d5339499bafdb8, 0xe371cc45bd8b63bd, 0xc77cd2c2f2b847c0,
+    0x48aa9d4fe185e2d7, 0xcf7be2706ff7b69b, 0xa131c5b6a01de3d5, 0x402af534ee476e71,
+    0x17b900f86310816b, 0x23b5dcc6bf2aeac6, 0x02041ec4cb8ccc5d, 0xa2c9bedc7721362a,
+    0x191da846f7dc1610, 0x3a6d6894d9a711a1, 0xebc678d663104f8b, 0xf945ca83f030481b,
+    0x8171a333d3947123, 0xc5b50c351de990e3, 0xfea6e8a01c3e5d83, 0xb1681a857af86f22,
+    0x9b478a37239b1d8e, 0x7949e9a0975ecf31, 0x008b453db9d5fa8f, 0x713c61264486c04e,
+    0x46548f009a89ad5c, 0x1b2cbd69a7e106c2, 0x7100994461fb0581, 0x52775b9c7405b578,
+    0xc9a93b9ad08b8258, 0xc9aa6f2b31555715, 0x5172bb6db1c816c5, 0x6e548bef47395d55,
+    0xe0768d4c3b4aeac0, 0xc4d45632ef66cc62, 0x73adcf04fa336bcf, 0x9b8e9d3960584069,
+    0xd6e2fbdedcbf65b7, 0xbba1a8094d144630, 0x824e74481c8d6f2e, 0x1865ff7e54c579c4,
+    0xe91b31ebd9b38101, 0x8398b10e19ae7740, 0x13dbd0bb05004f97, 0x59a878d078715d50,
+    0x59c87dcc4a1fdbbe, 0x25de90f01811af4c, 0x5a57bf0c1d27e6ce, 0xac7b0ec9652e2d0d,
+    0x0b079386554407d0, 0xf3f42d11e1c849de, 0x1c6aee2f5327f264, 0xb1012310bca23cca,
+    0x2c50c7f6ec4fa31e, 0xa93a3223ec951946, 0xacf5defccfcf06d7, 0xbc3e0c530198cf4f,
+    0xabf3ceca37732140, 0xb508a3a90c09ed0f, 0x84b414d0fe20c8b2, 0x88dabd445cb00c1d,
+    0xda14138aac9237c6, 0x1d3ad98997accf6c, 0x2ae5466a73e8ae91, 0x553f5a0bba25ee74,
+    0x423244b1604a3440, 0xde77c6423bf6bf2b, 0xb757fbec179084a7, 0x4b7aff36293d96b6,
+    0xb4b1f0beb925a80e, 0x1f6c26f06d2945f5, 0x17fac88b3ab268c1, 0xeb07f54998f9fce8,
+    0x53dc375f8c5456a4, 0x0e5c91b7d7913654, 0x55714a2f0e604b53, 0x0d4f3f426470a647,
+    0x92c2f24d52816565, 0x52d44211b748364c, 0xfa43b674ed318ab2, 0x3c8b6008df37f100,
+    0xf72abc061669c631, 0xd66896526588a615, 0x315885a64a3a8b2a, 0xfe4bc5cd681257b5,
+    0x8102dbd2a5c432ce, 0xa326f4491475d67d, 0x4d8f1804ac02a133, 0xdab422a57e05f4b0,
+    0x35e159c7816550bb, 0x21e180398faa00e1, 0x2d1c206252966b04, 0xd6eb0af87e06ac7d,
+    0x9d6537c84945ab73, 0x9c6ab9d87ff8202b, 0x685fe8f939f32de5, 0x5f725632f3ef6233,
+    0x2b2f8e4e5c36cb56, 0xed3050f2f22149f3, 0x5288fc9b1c12bda9, 0x25e9e83d2245473c,
+    0xb3f515b5f8000492, 0xd8e037222b3d9775, 0x36f87197854db877, 0xbc712dc475501983,
+    0x3518f49b313988fb, 0x939337de2658cb78, 0x15577d8517d30fe9, 0x964d996d5ae11567,
+    0x663954a989e160d9, 0x6eac624f33ddf5b0, 0x1ee266a4b40f8641, 0x816f2a46005ec803,
+    0x12bfe886d72254ab, 0x58f7c82de85fe2cd, 0x7839ce392a7c62c0, 0x95c8403321a4946f,
+    0xb5cef87d43780412, 0xc883dde8efe7b748, 0xcebb74bde7826b0a, 0xa52b766a9df17c10,
+    0x6bd8e4396888e12b, 0x5446961242a8207f, 0x5939c9b9bc38e94a, 0x036693c7632dd4d9,
+    0xdb394e180642e81f, 0x4db3bb2a9ba9b048, 0x11d5bfee007d966b, 0x1f3fb9202078b66a,
+    0x0fc717bc71582b7e, 0x361d648700830236, 0x783f23f20d845e77, 0xecdded651d19540f,
+    0xdf5ba4a324b3b344, 0xb542f581cf8ce19d, 0x0a5837dfe2cde04a, 0xe1ad57f423ebf7bc,
+    0x55b25a7cd414950c, 0x6b49c7649a85355b, 0xfedc04abff92e607, 0x0578259c37fef711,
+    0xd28223158d4082bb, 0x004e5f18b6bb7189, 0xfc4ea4dd675e6156, 0x8a553f5bf3eda721,
+    0x5972399c61411384, 0x94748076302984fe, 0xd67af8bc4f2efa24, 0x91c532afd1c5ffa5,
+    0x893d7ad77eaf0276, 0x30eb8bd5b8044d7c, 0xb0c95d5d230384b7, 0x931f8ddc69517fa2,
+    0x3ff049cb445322e3, 0x3c1ed932bedebc40, 0xc273ced18db0cdb0, 0x914b196e6a9845a3,
+    0xf26c5758ad83322b, 0x9ce3be51f023d8ee, 0xfe23a288e1a5e504, 0x00a42e26dbc4357c,
+    0x219ebeea1d10636c, 0x8aafaf3ca02a3505, 0x645f7935c3761342, 0x61f82532ac767bc0,
+    0x151c8b2cabd9781c, 0x9cc0381ca12e55e1, 0x7facad886875393c, 0xaed2480fe037f01b,
+    0x16f48c81823fd8e3, 0x16ece5b9556a00ce, 0x09fb6fba626e0866, 0xeb19796b6c90beae,
+    0x1d8e60c0e5d716cb, 0x8b30748f77d949b8, 0x32f14f025a666640, 0x5b8f6f4066852971,
+    0xe0616e20dd05d72b, 0xc89a9f81b1ef3686, 0x79c674c28624538b, 0xee3a162fd8ab1835,
+    0x83606d6e210aee52, 0x9956987c4c5ca0e3, 0xdbab67efa8eb056e, 0x59aff2860ec263d4,
+    0x3e1e3c0c5e653bb4, 0xf52b82fb57756821, 0x9c1af80e1d5c4c63, 0x59be9827d60eb5b3,
+    0x1532de21a7da6e7a, 0x0d218a50ea0344ae, 0x1776d9c95bdd84d3, 0xe02f7cd3788e6591,
+    0xd384480018dc3a8b, 0x6543d36150efa07f, 0x69bc8ee8b9462624, 0xb22db742e8615ab9,
+];
+
+// Parameters needed to re-derive the master key (and every chunk key) from a
+// user-supplied passphrase. No chunk key material is ever stored; only the
+// salt, the Argon2id cost parameters, and a verifier hash travel in metadata.
+#[derive(Deserialize, Serialize)]
+struct KdfParams {
+    salt: String,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    verifier: String,
+}
+
+impl KdfParams {
+
+    fn generate(passphrase: &str) -> Result<Self> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let mut kdf = KdfParams {
+            salt: base64::encode(salt),
+            m_cost: KDF_M_COST,
+            t_cost: KDF_T_COST,
+            p_cost: KDF_P_COST,
+            verifier: String::new(),
         };
-        let key = Key::from(random());
-        Self { id, key }
+        let master_key = kdf.derive_master_key(passphrase)?;
+        kdf.verifier = kdf.hash(&master_key)?;
+        Ok(kdf)
     }
 
-    pub fn id_string(&self) -> String {
-        let mut result = self.id.simple().to_string();
-        result.make_ascii_lowercase();
-        result
+    fn argon2(&self) -> Result<Argon2<'static>> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(MASTER_KEY_LEN))
+            .map_err(|e| anyhow!("invalid Argon2 parameters: {}", e))?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
     }
 
-    pub fn key_string(&self) -> String {
-        base64::encode(self.key.as_slice()).to_string()
+    fn salt(&self) -> Result<Vec<u8>> {
+        base64::decode(&self.salt).map_err(|_| anyhow!("malformed KDF salt"))
     }
 
-    pub fn nonce(&self) -> Nonce<U12> {
-        let bytes = &self.key.as_slice()[..12];
-        *Nonce::from_slice(bytes)
+    fn derive_master_key(&self, passphrase: &str) -> Result<MasterKey> {
+        let salt = self.salt()?;
+        let mut master_key = [0u8; MASTER_KEY_LEN];
+        self.argon2()?
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut master_key)
+            .map_err(|e| anyhow!("key derivation failed: {}", e))?;
+        Ok(master_key)
     }
 
-    pub fn encrypt(&self, buffer: &mut Vec<u8>) {
-        let cipher = Aes128Gcm::new(&self.key);
-        buffer.reserve(16);
-        cipher.encrypt_in_place(&self.nonce(), &[], buffer).unwrap();
+    // Hashes the master key itself (not the passphrase) with the same
+    // Argon2id parameters, so a wrong passphrase can be rejected up front
+    // instead of surfacing as a GCM authentication failure deep in decryption.
+    fn hash(&self, master_key: &MasterKey) -> Result<String> {
+        let salt = self.salt()?;
+        let mut verifier = [0u8; MASTER_KEY_LEN];
+        self.argon2()?
+            .hash_password_into(master_key, &salt, &mut verifier)
+            .map_err(|e| anyhow!("verifier derivation failed: {}", e))?;
+        Ok(base64::encode(verifier))
     }
 
-    pub fn decrypt(&self, buffer: &mut Vec<u8>) {
-        let cipher = Aes128Gcm::new(&self.key);
-        cipher.decrypt_in_place(&self.nonce(), &[], buffer).unwrap();
+    fn verify(&self, passphrase: &str) -> Result<MasterKey> {
+        let master_key = self.derive_master_key(passphrase)?;
+        if self.hash(&master_key)? != self.verifier {
+            return Err(anyhow!("incorrect passphrase"));
+        }
+        Ok(master_key)
     }
 
 }
 
+// The AEAD cipher used to encrypt a file's chunks. Stored as a single byte in
+// `Metadata` so old archives stay decryptable even as the default changes.
+#[derive(Clone, Copy, PartialEq)]
 #[derive(Deserialize, Serialize)]
-struct ChunkIntermediate {
-    id: String,
-    key: String,
+#[serde(into = "u8", try_from = "u8")]
+pub enum EncryptionType {
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
 }
 
-impl From<Chunk> for ChunkIntermediate {
+impl EncryptionType {
 
-    fn from(chunk: Chunk) -> Self {
-        ChunkIntermediate {
-            id: chunk.id_string(),
-            key: chunk.key_string(),
+    fn key_len(&self) -> usize {
+        match self {
+            EncryptionType::Aes128Gcm => 16,
+            EncryptionType::Aes256Gcm => 32,
+            EncryptionType::ChaCha20Poly1305 => 32,
         }
     }
 
 }
 
-impl TryFrom<ChunkIntermediate> for Chunk {
+impl From<EncryptionType> for u8 {
+    fn from(encryption_type: EncryptionType) -> Self {
+        match encryption_type {
+            EncryptionType::Aes128Gcm => 0,
+            EncryptionType::Aes256Gcm => 1,
+            EncryptionType::ChaCha20Poly1305 => 2,
+        }
+    }
+}
 
+impl TryFrom<u8> for EncryptionType {
     type Error = &'static str;
 
-    fn try_from(ci: ChunkIntermediate) -> Result<Self, Self::Error> {
-        let id = Uuid::parse_str(&ci.id).map_err(|_| "malformed ID")?;
-        let key = {
-            let bytes = base64::decode(&ci.key).map_err(|_| "malformed key")?;
-            *Key::from_slice(&bytes)
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        match byte {
+            0 => Ok(EncryptionType::Aes128Gcm),
+            1 => Ok(EncryptionType::Aes256Gcm),
+            2 => Ok(EncryptionType::ChaCha20Poly1305),
+            _ => Err("unrecognized encryption type"),
+        }
+    }
+}
+
+// The lowercase hex form of a chunk id, used as its on-disk filename. Kept
+// free of the `Chunk` it names so callers that only have a `ChunkRecord`
+// (e.g. a job dispatcher deciding where to read a chunk from) don't need to
+// derive the chunk's key first just to find its path.
+fn format_chunk_id(id: &Uuid) -> String {
+    let mut result = id.simple().to_string();
+    result.make_ascii_lowercase();
+    result
+}
+
+#[derive(Clone)]
+pub struct Chunk {
+    id: Uuid,
+    key: Vec<u8>,
+    nonce: Nonce<U12>,
+    encryption_type: EncryptionType,
+}
+
+impl Chunk {
+
+    fn derive_key(id: &Uuid, master_key: &MasterKey, encryption_type: EncryptionType) -> Vec<u8> {
+        let hk = Hkdf::<Sha256>::new(None, master_key);
+        let mut key_bytes = vec![0u8; encryption_type.key_len()];
+        hk.expand(id.as_bytes(), &mut key_bytes)
+            .expect("key_len is a valid HKDF-SHA256 output length");
+        key_bytes
+    }
+
+    // Reconstructs a chunk for decryption from its recorded id and nonce.
+    fn from_record(
+        record: &ChunkRecord,
+        master_key: &MasterKey,
+        encryption_type: EncryptionType,
+    ) -> Result<Self> {
+        let key = Self::derive_key(&record.id, master_key, encryption_type);
+        let nonce_bytes = base64::decode(&record.nonce)
+            .map_err(|_| anyhow!("malformed chunk nonce"))?;
+        if nonce_bytes.len() != 12 {
+            return Err(anyhow!("malformed chunk nonce"));
+        }
+        let nonce = *Nonce::from_slice(&nonce_bytes);
+        Ok(Self { id: record.id, key, nonce, encryption_type })
+    }
+
+    pub fn random(master_key: &MasterKey, encryption_type: EncryptionType) -> Self {
+        let mut bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut bytes);
+        let id = Uuid::from_bytes(&bytes).unwrap();
+        let key = Self::derive_key(&id, master_key, encryption_type);
+        let mut nonce_bytes = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce_bytes);
+        let nonce = *Nonce::from_slice(&nonce_bytes);
+        Self { id, key, nonce, encryption_type }
+    }
+
+    // Derived from `master_key` via a different HKDF info string than
+    // `derive_key`, so the nonce stays independent of the key (chunk0-4)
+    // while still being deterministic for a given id.
+    fn derive_nonce(id: &Uuid, master_key: &MasterKey) -> Nonce<U12> {
+        let hk = Hkdf::<Sha256>::new(None, master_key);
+        let mut nonce_bytes = [0u8; 12];
+        let info: Vec<u8> = id.as_bytes().iter().copied().chain(*b"nonce").collect();
+        hk.expand(&info, &mut nonce_bytes)
+            .expect("12 is a valid HKDF-SHA256 output length");
+        *Nonce::from_slice(&nonce_bytes)
+    }
+
+    // A chunk whose id is the SHA-256 hash of its own plaintext rather than
+    // randomly generated, so every occurrence of identical plaintext derives
+    // the same id, key, and nonce and therefore the same ciphertext. Lets a
+    // caller write a repeated chunk's ciphertext at most once per run. Still
+    // scoped to this run's `master_key`, so it does not dedup across runs.
+    pub fn content_addressed(
+        master_key: &MasterKey,
+        encryption_type: EncryptionType,
+        plaintext: &[u8],
+    ) -> Self {
+        let digest = Sha256::digest(plaintext);
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&digest[..16]);
+        let id = Uuid::from_bytes(&bytes).unwrap();
+        let key = Self::derive_key(&id, master_key, encryption_type);
+        let nonce = Self::derive_nonce(&id, master_key);
+        Self { id, key, nonce, encryption_type }
+    }
+
+    pub fn id_string(&self) -> String {
+        format_chunk_id(&self.id)
+    }
+
+    fn record(&self) -> ChunkRecord {
+        ChunkRecord { id: self.id, nonce: base64::encode(self.nonce.as_slice()) }
+    }
+
+    pub fn encrypt(&self, buffer: &mut Vec<u8>, aad: &[u8]) {
+        buffer.reserve(16);
+        match self.encryption_type {
+            EncryptionType::Aes128Gcm => {
+                let cipher = Aes128Gcm::new(Key::<U16>::from_slice(&self.key));
+                cipher.encrypt_in_place(&self.nonce, aad, buffer).unwrap();
+            },
+            EncryptionType::Aes256Gcm => {
+                let cipher = Aes256Gcm::new(Key::<U32>::from_slice(&self.key));
+                cipher.encrypt_in_place(&self.nonce, aad, buffer).unwrap();
+            },
+            EncryptionType::ChaCha20Poly1305 => {
+                let cipher = ChaCha20Poly1305::new(Key::<U32>::from_slice(&self.key));
+                cipher.encrypt_in_place(&self.nonce, aad, buffer).unwrap();
+            },
+        }
+    }
+
+    // A tampered or truncated chunk, or a chunk file holding another chunk's
+    // ciphertext, fails AEAD authentication here; reordered non-final chunk
+    // records are not detected, since those chunks carry no AAD. Return that
+    // as an error instead of unwrapping so a malicious input can't take down
+    // the decrypting thread (and, via its join handle, the caller).
+    pub fn decrypt(&self, buffer: &mut Vec<u8>, aad: &[u8]) -> Result<()> {
+        let result = match self.encryption_type {
+            EncryptionType::Aes128Gcm => {
+                let cipher = Aes128Gcm::new(Key::<U16>::from_slice(&self.key));
+                cipher.decrypt_in_place(&self.nonce, aad, buffer)
+            },
+            EncryptionType::Aes256Gcm => {
+                let cipher = Aes256Gcm::new(Key::<U32>::from_slice(&self.key));
+                cipher.decrypt_in_place(&self.nonce, aad, buffer)
+            },
+            EncryptionType::ChaCha20Poly1305 => {
+                let cipher = ChaCha20Poly1305::new(Key::<U32>::from_slice(&self.key));
+                cipher.decrypt_in_place(&self.nonce, aad, buffer)
+            },
         };
-        Ok(Chunk { id, key })
+        result.map_err(|_| anyhow!("chunk authentication failed"))
     }
 
 }
+
+const FINAL_CHUNK_MARKER: u8 = 0xff;
+
+// Associated data binding a chunk's ciphertext to its position in the file,
+// following the chunked-AEAD construction used by OpenPGP's AEAD packets.
+// Only the final chunk is encrypted with this AAD; content-addressed
+// non-final chunks use an empty AAD. Adding or dropping a chunk record
+// changes `total_chunks`, and moving the final chunk changes its `index`, so
+// the final chunk then fails authentication; reordering non-final chunk
+// records leaves every chunk authenticating.
+fn chunk_aad(index: u64, total_chunks: u64, file_len: u64, is_final: bool) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(25);
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad.extend_from_slice(&total_chunks.to_be_bytes());
+    aad.extend_from_slice(&file_len.to_be_bytes());
+    if is_final {
+        aad.push(FINAL_CHUNK_MARKER);
+    }
+    aad
+}
+
+// Pads `buffer` to a multiple of 16 bytes per PKCS#7: append `n` bytes each
+// equal to `n`, where `n` is in `1..=16`, always adding a full block if
+// `buffer` is already aligned. This makes the final chunk self-describing, so
+// decryption no longer needs an out-of-band length to strip trailing padding.
+fn pkcs7_pad(buffer: &mut Vec<u8>) {
+    let pad_len = 16 - (buffer.len() % 16);
+    buffer.resize(buffer.len() + pad_len, pad_len as u8);
+}
+
+// Validates and strips PKCS#7 padding, rejecting any chunk whose trailing
+// padding bytes aren't all equal to the final byte value.
+fn pkcs7_unpad(buffer: &mut Vec<u8>) -> Result<()> {
+    let pad_len = *buffer.last().ok_or_else(|| anyhow!("empty chunk"))? as usize;
+    if pad_len == 0 || pad_len > 16 || pad_len > buffer.len() {
+        return Err(anyhow!("invalid PKCS#7 padding"));
+    }
+    let start = buffer.len() - pad_len;
+    if !buffer[start..].iter().all(|&b| b as usize == pad_len) {
+        return Err(anyhow!("invalid PKCS#7 padding"));
+    }
+    buffer.truncate(start);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // A chunk count above `5 * num_workers` is what exercises the
+    // decrypt_file dispatch path where chunk0-7's channel deadlock showed up.
+    #[test]
+    fn encrypt_decrypt_round_trip() {
+        let dir = std::env::temp_dir().join(format!("filecrypt-test-{}", std::process::id()));
+        let path_in = dir.join("plaintext");
+        let path_chunks = dir.join("chunks");
+        let path_out = dir.join("roundtrip");
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path_in, &data).unwrap();
+        let passphrase = "correct horse battery staple";
+        let metadata =
+            encrypt_file(
+                &path_in, &path_chunks, 4096, None, None, passphrase, EncryptionType::Aes128Gcm,
+                Some(2),
+            )
+            .unwrap();
+        assert!(metadata.chunk_records.len() > 10);
+        decrypt_file(&path_chunks, &path_out, &metadata, passphrase, Some(2)).unwrap();
+        let round_tripped = fs::read(&path_out).unwrap();
+        assert_eq!(round_tripped, data);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,27 +1,38 @@
 mod lib;
 
-use aes_gcm::Key;
 use anyhow::{anyhow, Result};
-use base64;
 use std::env;
+use std::fs;
 use std::path::Path;
 use std::slice::Iter;
-use uuid::Uuid;
 
 use crate::lib::*;
 
 const USAGE_E: &str = r#"
 Encrypts a file. If -c is not provided, the entire file is encrypted as a
-single block of minimal size.
+single block. Otherwise the file is split into content-defined chunks
+averaging roughly `avg_size` bytes, so an insertion near the start of the
+file only reshuffles the chunk(s) it touches rather than every chunk after
+it. Chunks with identical content are only written to disk once, but only
+within this one run -- re-running on a changed file later does not reuse
+ciphertext from the earlier run.
 
 Options:
-    -c chunk_len    write chunks of this many bytes
+    -c avg_size     target average chunk size, in bytes
+    -n min_size     smallest allowed chunk size (default: avg_size / 4)
+    -x max_size     largest allowed chunk size (default: avg_size * 4)
     -o out_path     write to this directory
+    -p passphrase   passphrase to derive the master key from
+    -a algorithm    aes128 (default), aes256, or chacha20
+    -j num_workers  number of worker threads (default: available parallelism)
 "#;
 
 const USAGE_D: &str = r#"
-Decrypt a file. Each chunk should be specified as its filename followed by its
-key (in base 64), separated by a colon.
+Decrypts a file previously encrypted with this tool.
+
+Options:
+    -p passphrase   passphrase the file was encrypted with
+    -j num_workers  number of worker threads (default: available parallelism)
 "#;
 
 enum Params {
@@ -32,14 +43,20 @@ enum Params {
 struct EncryptParams {
     path_in: String,
     path_out: String,
-    chunk_len: Option<usize>,
+    avg_size: Option<usize>,
+    min_size: Option<usize>,
+    max_size: Option<usize>,
+    passphrase: String,
+    encryption_type: EncryptionType,
+    num_workers: Option<usize>,
 }
 
 struct DecryptParams {
     path_in: String,
     path_out: String,
-    file_len: usize,
-    chunks: Vec<Chunk>,
+    metadata_path: String,
+    passphrase: String,
+    num_workers: Option<usize>,
 }
 
 fn main() {
@@ -52,7 +69,10 @@ fn run() -> Result<()> {
     let args: Vec<String> = env::args().collect();
     match parse_args(args.iter())? {
         Params::Encrypt(params) => {
-            let EncryptParams { path_in, path_out, chunk_len } = params;
+            let EncryptParams {
+                path_in, path_out, avg_size, min_size, max_size, passphrase, encryption_type,
+                num_workers,
+            } = params;
             let path_in = Path::new(&path_in);
             let file_len = path_in.metadata()?.len();
             if file_len == 0 {
@@ -60,20 +80,25 @@ fn run() -> Result<()> {
             }
             let path_out = Path::new(&path_out);
             let metadata =
-                match chunk_len {
-                    Some(chunk_len) => encrypt_file(path_in, path_out, chunk_len),
-                    None => encrypt_file_unchunked(path_in, path_out),
+                match avg_size {
+                    Some(avg_size) =>
+                        encrypt_file(
+                            path_in, path_out, avg_size, min_size, max_size, &passphrase,
+                            encryption_type, num_workers,
+                        )?,
+                    None =>
+                        encrypt_file_unchunked(path_in, path_out, &passphrase, encryption_type)?,
                 };
-            println!("{}", metadata.to_string());
+            println!("{}", metadata);
             Ok(())
         },
         Params::Decrypt(params) => {
-            let DecryptParams { path_in, path_out, file_len, chunks } = params;
+            let DecryptParams { path_in, path_out, metadata_path, passphrase, num_workers } = params;
             let path_in = Path::new(&path_in);
             let path_out = Path::new(&path_out);
-            let chunk_len = path_in.join(chunks[0].filename()).metadata()?.len();
-            let metadata = Metadata::new(file_len, chunk_len as usize, chunks);
-            decrypt_file(path_in, path_out, &metadata);
+            let metadata_str = fs::read_to_string(&metadata_path)?;
+            let metadata: Metadata = metadata_str.parse()?;
+            decrypt_file(path_in, path_out, &metadata, &passphrase, num_workers)?;
             Ok(())
         },
     }
@@ -104,23 +129,40 @@ fn parse_e_args(mut args: Iter<String>) -> Result<EncryptParams> {
                 return Err(anyhow!(usage_e()));
             },
         };
-    let mut chunk_len = None;
+    let mut avg_size = None;
+    let mut min_size = None;
+    let mut max_size = None;
     let mut path_out = ".".to_string();
+    let mut passphrase = None;
+    let mut encryption_type = EncryptionType::Aes128Gcm;
+    let mut num_workers = None;
     loop {
         match (args.next().map(|s| s.as_str()), args.next()) {
-            (Some("-c"), Some(chunk_len_str)) => {
-                let chunk_len_parsed = chunk_len_str.parse::<usize>()?;
-                if chunk_len_parsed == 0 {
-                    return Err(anyhow!("Chunk length must not be zero"));
+            (Some("-c"), Some(avg_size_str)) => {
+                let avg_size_parsed = avg_size_str.parse::<usize>()?;
+                if avg_size_parsed == 0 {
+                    return Err(anyhow!("avg_size must not be zero"));
                 }
-                if chunk_len_parsed % 16 != 0 {
-                    return Err(anyhow!("Chunk length must be a multiple of 16"));
-                }
-                chunk_len = Some(chunk_len_parsed);
+                avg_size = Some(avg_size_parsed);
+            },
+            (Some("-n"), Some(min_size_str)) => {
+                min_size = Some(min_size_str.parse::<usize>()?);
+            },
+            (Some("-x"), Some(max_size_str)) => {
+                max_size = Some(max_size_str.parse::<usize>()?);
             },
             (Some("-o"), Some(path_out_str)) => {
                 path_out = path_out_str.to_string();
             },
+            (Some("-p"), Some(passphrase_str)) => {
+                passphrase = Some(passphrase_str.to_string());
+            },
+            (Some("-a"), Some(encryption_type_str)) => {
+                encryption_type = parse_encryption_type(encryption_type_str)?;
+            },
+            (Some("-j"), Some(num_workers_str)) => {
+                num_workers = Some(parse_num_workers(num_workers_str)?);
+            },
             (Some(_), _) => {
                 return Err(anyhow!(usage()));
             },
@@ -129,7 +171,30 @@ fn parse_e_args(mut args: Iter<String>) -> Result<EncryptParams> {
             },
         }
     }
-    Ok(EncryptParams { path_in, path_out, chunk_len })
+    let passphrase = match passphrase {
+        Some(passphrase) => passphrase,
+        None => return Err(anyhow!("A passphrase is required (-p)")),
+    };
+    Ok(EncryptParams {
+        path_in, path_out, avg_size, min_size, max_size, passphrase, encryption_type, num_workers,
+    })
+}
+
+fn parse_num_workers(s: &str) -> Result<usize> {
+    let num_workers = s.parse::<usize>()?;
+    if num_workers == 0 {
+        return Err(anyhow!("num_workers must not be zero"));
+    }
+    Ok(num_workers)
+}
+
+fn parse_encryption_type(s: &str) -> Result<EncryptionType> {
+    match s {
+        "aes128" => Ok(EncryptionType::Aes128Gcm),
+        "aes256" => Ok(EncryptionType::Aes256Gcm),
+        "chacha20" => Ok(EncryptionType::ChaCha20Poly1305),
+        _ => Err(anyhow!("unrecognized algorithm '{}' (expected aes128, aes256, or chacha20)", s)),
+    }
 }
 
 fn parse_d_args(mut args: Iter<String>) -> Result<DecryptParams> {
@@ -140,7 +205,6 @@ fn parse_d_args(mut args: Iter<String>) -> Result<DecryptParams> {
                 return Err(anyhow!(usage_d()));
             },
         };
-    let mut chunks = Vec::new();
     let path_out =
         match args.next() {
             Some(path_out) => path_out.to_string(),
@@ -148,45 +212,36 @@ fn parse_d_args(mut args: Iter<String>) -> Result<DecryptParams> {
                 return Err(anyhow!(usage_d()));
             },
         };
-    let file_len =
+    let metadata_path =
         match args.next() {
-            Some(file_len_str) => file_len_str.parse::<usize>()?,
+            Some(metadata_path) => metadata_path.to_string(),
             None => {
                 return Err(anyhow!(usage_d()));
             },
         };
-        const MSG: &str = "Malformed chunk specifier";
+    let mut passphrase = None;
+    let mut num_workers = None;
     loop {
-        match args.next() {
-            Some(arg) => {
-                if arg.starts_with('-') {
-                    break;
-                }
-                let split: Vec<&str> = arg.split(':').collect();
-                if split.len() != 2 {
-                    return Err(anyhow!(MSG));
-                }
-                let id = {
-                    let string = split[0];
-                    Uuid::parse_str(string).map_err(|_| anyhow!(MSG))?
-                };
-                let key = {
-                    let string = split[1];
-                    let bytes = base64::decode(string).map_err(|_| anyhow!(MSG))?;
-                    *Key::from_slice(&bytes)
-                };
-                let chunk = Chunk::new(id, key);
-                chunks.push(chunk);
+        match (args.next().map(|s| s.as_str()), args.next()) {
+            (Some("-p"), Some(passphrase_str)) => {
+                passphrase = Some(passphrase_str.to_string());
             },
-            None => {
+            (Some("-j"), Some(num_workers_str)) => {
+                num_workers = Some(parse_num_workers(num_workers_str)?);
+            },
+            (Some(_), _) => {
+                return Err(anyhow!(usage_d()));
+            },
+            (None, _) => {
                 break;
             },
         }
     }
-    if chunks.is_empty() {
-        return Err(anyhow!("No chunks specified"));
-    }
-    Ok(DecryptParams { path_in, path_out, file_len, chunks })
+    let passphrase = match passphrase {
+        Some(passphrase) => passphrase,
+        None => return Err(anyhow!("A passphrase is required (-p)")),
+    };
+    Ok(DecryptParams { path_in, path_out, metadata_path, passphrase, num_workers })
 }
 
 fn program_name() -> String {
@@ -207,7 +262,7 @@ fn usage_e() -> String {
 
 fn usage_d() -> String {
     format!(
-        "Usage: {} d <path_in> <path_out> <file_len> (<chunk> ...)\n{}",
+        "Usage: {} d <path_in> <path_out> <metadata_path> [options]\n{}",
         program_name(),
         USAGE_D,
     )